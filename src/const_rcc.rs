@@ -7,15 +7,61 @@ use crate::{
     time::Hertz,
 };
 
+#[cfg(feature = "connectivity")]
+use crate::pac::rcc::cfgr2::PREDIV1SRC_A;
+
 /// Type to get the clock configuration in a const context.
 #[derive(Debug)]
 pub struct ClockConfig {
     hse: Option<u32>,
+    hse_bypass: bool,
     hclk: Option<u32>,
     pclk1: Option<u32>,
     pclk2: Option<u32>,
     sysclk: Option<u32>,
     adcclk: Option<u32>,
+    tolerance: u32,
+}
+
+/// Names the clock that a [`ClockError`] refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// System clock.
+    SysClk,
+    /// AHB clock.
+    HClk,
+    /// APB1 clock.
+    PClk1,
+    /// APB2 clock.
+    PClk2,
+    /// ADC clock.
+    AdcClk,
+}
+
+/// Error returned by [`ClockConfig::try_get_config`] when a requested clock can't be
+/// realised on the target device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockError {
+    /// The clock exceeds the maximum frequency allowed for this device.
+    TooHigh {
+        /// The clock that is out of range.
+        clock: Clock,
+        /// The frequency that was requested, in Hz, when one was set for this clock.
+        requested: Option<u32>,
+        /// The frequency that would actually be produced, in Hz.
+        actual: u32,
+        /// The maximum frequency allowed for this clock, in Hz.
+        limit: u32,
+    },
+    /// The closest frequency the clock tree can produce is outside the requested tolerance.
+    Unreachable {
+        /// The clock that could not be matched.
+        clock: Clock,
+        /// The frequency that was requested, in Hz.
+        requested: u32,
+        /// The frequency that would actually be produced, in Hz.
+        actual: u32,
+    },
 }
 
 /// Calculated configuration to be passed to the RCC.
@@ -29,6 +75,15 @@ pub struct RccConfig {
     pub(crate) ppre2_bits: u8,
     pub(crate) hpre_bits: u8,
     pub(crate) usbpre: USBPRE_A,
+    #[cfg(feature = "connectivity")]
+    pub(crate) prediv1_bits: u8,
+    #[cfg(feature = "connectivity")]
+    pub(crate) prediv2_bits: u8,
+    #[cfg(feature = "connectivity")]
+    pub(crate) pll2mul_bits: u8,
+    #[cfg(feature = "connectivity")]
+    pub(crate) prediv1_src: PREDIV1SRC_A,
+    pub(crate) hsebyp: bool,
     pub(crate) sysclk: u32,
     pub(crate) hclk: u32,
     pub(crate) pclk1: u32,
@@ -44,19 +99,40 @@ impl ClockConfig {
     pub const fn new() -> Self {
         Self {
             hse: None,
+            hse_bypass: false,
             hclk: None,
             pclk1: None,
             pclk2: None,
             sysclk: None,
             adcclk: None,
+            tolerance: 0,
         }
     }
 
+    /// Sets the tolerance, in percent, allowed between a requested clock and the frequency that
+    /// the clock tree can actually produce in [`try_get_config`](Self::try_get_config). Defaults to
+    /// `0`, which requires an exact match. Has no effect on the clamping [`get_config`](Self::get_config).
+    pub const fn tolerance(mut self, percent: u32) -> Self {
+        self.tolerance = percent;
+        self
+    }
+
     /// Uses HSE (external oscillator) instead of HSI (internal RC oscillator) as the clock source.
     /// Will result in a hang if an external oscillator is not connected or it fails to start.
     /// The frequency specified must be the frequency of the external oscillator.
     pub const fn use_hse(mut self, freq: Hertz) -> Self {
         self.hse = Some(freq.0);
+        self.hse_bypass = false;
+        self
+    }
+
+    /// Uses an external clock source fed directly to the OSC_IN pin (HSE bypass mode) instead of a
+    /// crystal/ceramic oscillator. Sets the HSEBYP bit so the RCC skips the oscillator startup
+    /// circuitry, which would otherwise hang when no crystal is present.
+    /// The frequency specified must be the frequency of the external clock.
+    pub const fn use_hse_bypass(mut self, freq: Hertz) -> Self {
+        self.hse = Some(freq.0);
+        self.hse_bypass = true;
         self
     }
 
@@ -102,21 +178,42 @@ impl ClockConfig {
         } else {
             pllsrcclk
         };
-        let pllmul = sysclk / pllsrcclk;
+        // Round to the nearest achievable multiplier instead of always truncating downwards, so
+        // that e.g. a requested 70 MHz lands on the closest legal sysclk rather than the next
+        // multiple below it.
+        let pllmul = (2 * sysclk + pllsrcclk) / (2 * pllsrcclk);
 
+        #[cfg(not(feature = "connectivity"))]
         let (pllmul_bits, sysclk) = if pllmul == 1 {
             let real_sysclk = if let Some(hse) = self.hse { hse } else { HSI };
             (None, real_sysclk)
         } else {
-            #[cfg(not(feature = "connectivity"))]
             let pllmul = u32_min(u32_max(pllmul, 2), 16);
-
-            #[cfg(feature = "connectivity")]
-            let pllmul = u32_min(u32_max(pllmul, 4), 9);
-
             (Some(pllmul as u8 - 2), pllsrcclk * pllmul)
         };
 
+        // On connectivity-line parts the main PLL can be fed from PLL2 through PREDIV1, which makes
+        // frequencies reachable that the plain `HSE -> PREDIV1 -> PLL` path can't express.
+        #[cfg(feature = "connectivity")]
+        let (pllmul_bits, prediv1_bits, prediv2_bits, pll2mul_bits, prediv1_src, sysclk) =
+            if pllmul == 1 {
+                let real_sysclk = if let Some(hse) = self.hse { hse } else { HSI };
+                (None, 0, 0, 0, PREDIV1SRC_A::HSE, real_sysclk)
+            } else if let Some(hse) = self.hse {
+                connectivity_pll(hse, sysclk)
+            } else {
+                // Off the HSI there is no PREDIV/PLL2 tree, so the direct multiplier is all we have.
+                let pllmul = u32_min(u32_max(pllmul, 4), 9);
+                (
+                    Some(pllmul as u8 - 2),
+                    0,
+                    0,
+                    0,
+                    PREDIV1SRC_A::HSE,
+                    pllsrcclk * pllmul,
+                )
+            };
+
         let hpre_bits = if let Some(hclk) = self.hclk {
             match sysclk / hclk {
                 0 | 1 => 0b0111,
@@ -175,6 +272,10 @@ impl ClockConfig {
             LATENCY_A::WS2
         };
 
+        // Value-line parts top out at 24 MHz, which is the zero-wait-state region of the same table.
+        #[cfg(feature = "stm32f100")]
+        let latency = LATENCY_A::WS0;
+
         // the USB clock is only valid if an external crystal is used, the PLL is enabled, and the
         // PLL output frequency is a supported one.
         // usbpre == false: divide clock by 1.5, otherwise no division
@@ -212,6 +313,15 @@ impl ClockConfig {
             ppre2_bits,
             hpre_bits,
             usbpre,
+            #[cfg(feature = "connectivity")]
+            prediv1_bits,
+            #[cfg(feature = "connectivity")]
+            prediv2_bits,
+            #[cfg(feature = "connectivity")]
+            pll2mul_bits,
+            #[cfg(feature = "connectivity")]
+            prediv1_src,
+            hsebyp: self.hse_bypass,
             sysclk,
             hclk,
             pclk1,
@@ -222,6 +332,194 @@ impl ClockConfig {
             usbclk_valid,
         }
     }
+
+    /// Calculates the configuration like [`get_config`](Self::get_config), but validates the result
+    /// against the device limits and the requested [`tolerance`](Self::tolerance) instead of
+    /// silently clamping.
+    ///
+    /// SYSCLK and HCLK are capped at 72 MHz (64 MHz when running off the HSI, i.e. without HSE;
+    /// 24 MHz on value-line `stm32f100` parts, with or without HSE), PCLK1 at 36 MHz, PCLK2 at 72 MHz and ADCCLK at 14 MHz. Each requested clock is then checked
+    /// against the frequency the clock tree would actually produce; a PLL multiplier or prescaler
+    /// ratio that had to be clamped shows up here as an [`ClockError::Unreachable`] rather than a
+    /// silently wrong configuration.
+    pub fn try_get_config(self) -> Result<RccConfig, ClockError> {
+        let tolerance = self.tolerance;
+        let has_hse = self.hse.is_some();
+        let req_sysclk = self.sysclk;
+        let req_hclk = self.hclk;
+        let req_pclk1 = self.pclk1;
+        let req_pclk2 = self.pclk2;
+        let req_adcclk = self.adcclk;
+
+        let config = self.get_config();
+
+        #[cfg(feature = "stm32f100")]
+        let sysclk_max = {
+            let _ = has_hse;
+            24_000_000
+        };
+        #[cfg(not(feature = "stm32f100"))]
+        let sysclk_max = if has_hse { 72_000_000 } else { 64_000_000 };
+        check_ceiling(Clock::SysClk, req_sysclk, config.sysclk, sysclk_max)?;
+        check_ceiling(Clock::HClk, req_hclk, config.hclk, sysclk_max)?;
+        check_ceiling(Clock::PClk1, req_pclk1, config.pclk1, 36_000_000)?;
+        check_ceiling(Clock::PClk2, req_pclk2, config.pclk2, 72_000_000)?;
+        check_ceiling(Clock::AdcClk, req_adcclk, config.adcclk, 14_000_000)?;
+
+        check_reachable(Clock::SysClk, req_sysclk, config.sysclk, tolerance)?;
+        check_reachable(Clock::HClk, req_hclk, config.hclk, tolerance)?;
+        check_reachable(Clock::PClk1, req_pclk1, config.pclk1, tolerance)?;
+        check_reachable(Clock::PClk2, req_pclk2, config.pclk2, tolerance)?;
+        check_reachable(Clock::AdcClk, req_adcclk, config.adcclk, tolerance)?;
+
+        Ok(config)
+    }
+}
+
+fn check_ceiling(
+    clock: Clock,
+    requested: Option<u32>,
+    actual: u32,
+    limit: u32,
+) -> Result<(), ClockError> {
+    if actual > limit {
+        Err(ClockError::TooHigh {
+            clock,
+            requested,
+            actual,
+            limit,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+fn check_reachable(
+    clock: Clock,
+    requested: Option<u32>,
+    actual: u32,
+    tolerance: u32,
+) -> Result<(), ClockError> {
+    if let Some(requested) = requested {
+        let diff = if actual > requested {
+            actual - requested
+        } else {
+            requested - actual
+        };
+        if u64::from(diff) * 100 > u64::from(requested) * u64::from(tolerance) {
+            return Err(ClockError::Unreachable {
+                clock,
+                requested,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Searches the connectivity-line clock tree for the combination that lands closest to `target`.
+///
+/// The direct `HSE -> PREDIV1 -> PLL (x4..9)` path is tried first; if it can't match exactly the
+/// PLL2 path `HSE -> PREDIV2 -> PLL2 -> PREDIV1 -> PLL` is swept as well. Returns the PLL multiplier
+/// bits (or `None` when the PLL is bypassed), the PREDIV1/PREDIV2/PLL2 register bits, the PREDIV1
+/// source selection and the resulting real sysclk.
+///
+/// For example 25 MHz -> PREDIV2 ÷5 -> 5 MHz -> PLL2 ×8 -> 40 MHz -> PREDIV1 ÷5 -> 8 MHz -> PLL ×9
+/// -> 72 MHz.
+#[cfg(feature = "connectivity")]
+const fn connectivity_pll(hse: u32, target: u32) -> (Option<u8>, u8, u8, u8, PREDIV1SRC_A, u32) {
+    let mut best_err = u32::MAX;
+    let mut best = (None, 0u8, 0u8, 0u8, PREDIV1SRC_A::HSE, hse);
+
+    // Direct path: HSE -> PREDIV1 -> PLL.
+    let mut prediv1 = 1;
+    while prediv1 <= 16 {
+        let pll_in = hse / prediv1;
+        // The main PLL input must stay within its 3..12 MHz window, same as the PLL2 path below.
+        if pll_in >= 3_000_000 && pll_in <= 12_000_000 {
+            let mut pllmul = 4;
+            while pllmul <= 9 {
+                let out = pll_in * pllmul;
+                let err = abs_diff(out, target);
+                if err < best_err {
+                    best_err = err;
+                    best = (
+                        Some(pllmul as u8 - 2),
+                        (prediv1 - 1) as u8,
+                        0,
+                        0,
+                        PREDIV1SRC_A::HSE,
+                        out,
+                    );
+                }
+                pllmul += 1;
+            }
+        }
+        prediv1 += 1;
+    }
+    if best_err == 0 {
+        return best;
+    }
+
+    // PLL2 path: HSE -> PREDIV2 -> PLL2 -> PREDIV1 -> PLL.
+    let pll2muls = [8, 9, 10, 11, 12, 13, 14, 16, 20];
+    let mut prediv2 = 1;
+    while prediv2 <= 16 {
+        let pll2_in = hse / prediv2;
+        // The PLL2 input must sit in its 3..5 MHz specification window.
+        if pll2_in >= 3_000_000 && pll2_in <= 5_000_000 {
+            let mut i = 0;
+            while i < pll2muls.len() {
+                let pll2mul = pll2muls[i];
+                let pll2_out = pll2_in * pll2mul;
+                let mut prediv1 = 1;
+                while prediv1 <= 16 {
+                    let pll_in = pll2_out / prediv1;
+                    // The main PLL input must stay within its 3..12 MHz window.
+                    if pll_in >= 3_000_000 && pll_in <= 12_000_000 {
+                        let mut pllmul = 4;
+                        while pllmul <= 9 {
+                            let out = pll_in * pllmul;
+                            let err = abs_diff(out, target);
+                            if err < best_err {
+                                best_err = err;
+                                let pll2mul_bits = if pll2mul <= 14 {
+                                    pll2mul as u8 - 2
+                                } else if pll2mul == 16 {
+                                    0b1110
+                                } else {
+                                    0b1111
+                                };
+                                best = (
+                                    Some(pllmul as u8 - 2),
+                                    (prediv1 - 1) as u8,
+                                    (prediv2 - 1) as u8,
+                                    pll2mul_bits,
+                                    PREDIV1SRC_A::PLL2,
+                                    out,
+                                );
+                            }
+                            pllmul += 1;
+                        }
+                    }
+                    prediv1 += 1;
+                }
+                i += 1;
+            }
+        }
+        prediv2 += 1;
+    }
+
+    best
+}
+
+#[cfg(feature = "connectivity")]
+const fn abs_diff(a: u32, b: u32) -> u32 {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
 }
 
 const fn u32_min(a: u32, b: u32) -> u32 {